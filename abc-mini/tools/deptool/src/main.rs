@@ -9,23 +9,89 @@ use clap::{Args, Parser, Subcommand};
 use fnv::FnvHashMap;
 use serde::Serialize;
 
+use std::collections::{BTreeMap, HashSet};
 use std::env;
 use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
 
+/// The file and line behind a libclang source location, used to point
+/// diagnostics at the offending directive or AST node.
+#[derive(Clone, Debug)]
+struct ErrLoc {
+    file: PathBuf,
+    line: u32,
+}
+
+impl fmt::Display for ErrLoc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file.display(), self.line)
+    }
+}
+
+impl From<cl::source::Location<'_>> for ErrLoc {
+    fn from(location: cl::source::Location<'_>) -> Self {
+        Self {
+            file: location
+                .file
+                .map(|file| file.get_path())
+                .unwrap_or(PathBuf::from("<unknown>".to_string())),
+            line: location.line,
+        }
+    }
+}
+
+fn token_errloc(token: &Token<'_>) -> ErrLoc {
+    token.get_location().get_file_location().into()
+}
+
+fn entity_errloc(entity: &Entity<'_>) -> ErrLoc {
+    entity
+        .get_location()
+        .map(|location| location.get_file_location().into())
+        .unwrap_or(ErrLoc {
+            file: PathBuf::from("<unknown>"),
+            line: 0,
+        })
+}
+
+/// Errors produced when libclang hands back an AST or token stream shape
+/// that a directive parser didn't anticipate (as opposed to a malformed
+/// directive body, which is reported as a plain `anyhow::Error`).
+#[derive(Debug, thiserror::Error)]
+enum DirectiveError {
+    #[error("{0}: expected entity kind {1:?}, found {2:?}")]
+    UnexpectedEntityKind(ErrLoc, EntityKind, EntityKind),
+    #[error("{0}: expected a comment token, found {1:?}")]
+    UnexpectedTokenKind(ErrLoc, TokenKind),
+    #[error("{0}: struct declaration should have a name")]
+    MissingStructName(ErrLoc),
+    #[error("{0}: struct field declaration should have a name")]
+    MissingFieldName(ErrLoc),
+    #[error("{0}: struct field declaration should have a type")]
+    MissingFieldType(ErrLoc),
+    #[error("{0}: function parameter should have a name")]
+    MissingParamName(ErrLoc),
+    #[error("{0}: function parameter should have a type")]
+    MissingParamType(ErrLoc),
+    #[error("{0}: function should have a return type")]
+    MissingReturnType(ErrLoc),
+    #[error("{0}: function should have arguments")]
+    MissingArguments(ErrLoc),
+    #[error("{0}: root entity should have a display name")]
+    MissingDisplayName(ErrLoc),
+    #[error("{0}: root entity should have a range")]
+    MissingRange(ErrLoc),
+}
+
 struct DirectiveTokenPrinter<'a, 'tu>(&'a Token<'tu>);
 
 impl fmt::Debug for DirectiveTokenPrinter<'_, '_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let location = self.0.get_location().get_file_location();
-        let file = location
-            .file
-            .map(|file| file.get_path())
-            .unwrap_or(PathBuf::from("<unknown>".to_string()));
+        let location = token_errloc(self.0);
         f.debug_struct("Directive")
             .field("contents", &self.0.get_spelling())
-            .field("file", &file)
+            .field("file", &location.file)
             .field("line", &location.line)
             .finish()
     }
@@ -71,30 +137,33 @@ struct StructDecl {
     fields: Vec<FieldDecl>,
 }
 
-fn parse_struct_decl(node: &Entity<'_>) -> StructDecl {
-    assert_eq!(node.get_kind(), EntityKind::StructDecl);
+fn parse_struct_decl(node: &Entity<'_>) -> Result<StructDecl, DirectiveError> {
+    if node.get_kind() != EntityKind::StructDecl {
+        return Err(DirectiveError::UnexpectedEntityKind(
+            entity_errloc(node),
+            EntityKind::StructDecl,
+            node.get_kind(),
+        ));
+    }
     let name = node
         .get_name()
-        .expect("struct declaration should have a name");
+        .ok_or_else(|| DirectiveError::MissingStructName(entity_errloc(node)))?;
     let fields = node
         .get_children()
         .into_iter()
-        .filter_map(|node| {
-            if node.get_kind() == EntityKind::FieldDecl {
-                let name = node
-                    .get_name()
-                    .expect("struct field declaration should have a name");
-                let ty = node
-                    .get_type()
-                    .expect("struct field declaration should have a type")
-                    .get_display_name();
-                Some(FieldDecl { name, ty })
-            } else {
-                None
-            }
+        .filter(|node| node.get_kind() == EntityKind::FieldDecl)
+        .map(|node| {
+            let name = node
+                .get_name()
+                .ok_or_else(|| DirectiveError::MissingFieldName(entity_errloc(&node)))?;
+            let ty = node
+                .get_type()
+                .ok_or_else(|| DirectiveError::MissingFieldType(entity_errloc(&node)))?
+                .get_display_name();
+            Ok(FieldDecl { name, ty })
         })
-        .collect();
-    StructDecl { name, fields }
+        .collect::<Result<Vec<_>, DirectiveError>>()?;
+    Ok(StructDecl { name, fields })
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -110,36 +179,42 @@ struct FuncDecl {
     params: Vec<ParamDecl>,
 }
 
-fn parse_fn_param(node: Entity<'_>) -> ParamDecl {
-    assert_eq!(node.get_kind(), EntityKind::ParmDecl);
+fn parse_fn_param(node: Entity<'_>) -> Result<ParamDecl, DirectiveError> {
+    if node.get_kind() != EntityKind::ParmDecl {
+        return Err(DirectiveError::UnexpectedEntityKind(
+            entity_errloc(&node),
+            EntityKind::ParmDecl,
+            node.get_kind(),
+        ));
+    }
     let name = node
         .get_name()
-        .expect("function parameter should have a name");
+        .ok_or_else(|| DirectiveError::MissingParamName(entity_errloc(&node)))?;
     let ty = node
         .get_type()
-        .expect("function parameter should have a type")
+        .ok_or_else(|| DirectiveError::MissingParamType(entity_errloc(&node)))?
         .get_display_name();
-    ParamDecl { name, ty }
+    Ok(ParamDecl { name, ty })
 }
 
-fn parse_fn_signature(decl: Declaration<'_>) -> FuncDecl {
+fn parse_fn_signature(decl: Declaration<'_>) -> Result<FuncDecl, DirectiveError> {
     let return_ty = decl
         .entity
         .get_result_type()
-        .expect("function should have a return type")
+        .ok_or_else(|| DirectiveError::MissingReturnType(entity_errloc(&decl.entity)))?
         .get_display_name();
     let params = decl
         .entity
         .get_arguments()
-        .expect("function should have arguments")
+        .ok_or_else(|| DirectiveError::MissingArguments(entity_errloc(&decl.entity)))?
         .into_iter()
         .map(parse_fn_param)
-        .collect();
-    FuncDecl {
+        .collect::<Result<Vec<_>, DirectiveError>>()?;
+    Ok(FuncDecl {
         name: decl.name,
         return_ty,
         params,
-    }
+    })
 }
 
 #[derive(Debug, Serialize)]
@@ -161,15 +236,30 @@ enum Directive {
         signatures: Vec<FuncDecl>,
     },
     DefinedInEnd,
+    Include {
+        path: PathBuf,
+    },
+    If {
+        expr: String,
+    },
+    Elif {
+        expr: String,
+    },
+    Else,
+    Endif,
 }
 
-fn get_nextline_tokens<'tu>(root: &Entity<'tu>, start: &Token<'tu>) -> Vec<Token<'tu>> {
+fn get_nextline_tokens<'tu>(
+    root: &Entity<'tu>,
+    start: &Token<'tu>,
+) -> Result<Vec<Token<'tu>>, DirectiveError> {
     let sourcefile = root
         .get_display_name()
-        .expect("root entity should have a display name");
+        .ok_or_else(|| DirectiveError::MissingDisplayName(entity_errloc(root)))?;
     let next_line = start.get_location().get_file_location().line + 1;
-    root.get_range()
-        .expect("root entity should have a range")
+    let tokens = root
+        .get_range()
+        .ok_or_else(|| DirectiveError::MissingRange(entity_errloc(root)))?
         .tokenize()
         .into_iter()
         .filter_map(|token| {
@@ -184,12 +274,13 @@ fn get_nextline_tokens<'tu>(root: &Entity<'tu>, start: &Token<'tu>) -> Vec<Token
         .skip_while(|(line, _)| line < &next_line)
         .take_while(|(line, _)| line == &next_line)
         .map(|(_, token)| token)
-        .collect()
+        .collect();
+    Ok(tokens)
 }
 
 fn parse_alias_of(root: &Entity<'_>, start: &Token<'_>, typename: String) -> Result<Directive> {
     let ctx = parse_ctx!(start);
-    let tokens = get_nextline_tokens(root, start);
+    let tokens = get_nextline_tokens(root, start).with_context(ctx)?;
     let struct_decl = root
         .get_translation_unit()
         .annotate(&tokens)
@@ -202,13 +293,13 @@ fn parse_alias_of(root: &Entity<'_>, start: &Token<'_>, typename: String) -> Res
         .with_context(ctx)?;
     Ok(Directive::AliasOf {
         typename,
-        alias: parse_struct_decl(&struct_decl),
+        alias: parse_struct_decl(&struct_decl).with_context(ctx)?,
     })
 }
 
 fn parse_defined_in(root: &Entity<'_>, start: &Token<'_>, filename: String) -> Result<Directive> {
     let ctx = parse_ctx!(start);
-    let tokens = get_nextline_tokens(root, start);
+    let tokens = get_nextline_tokens(root, start).with_context(ctx)?;
     let nodes = root
         .get_translation_unit()
         .annotate(&tokens)
@@ -221,7 +312,7 @@ fn parse_defined_in(root: &Entity<'_>, start: &Token<'_>, filename: String) -> R
         .with_context(ctx)?;
     Ok(Directive::DefinedIn {
         filename,
-        signature: parse_fn_signature(decl),
+        signature: parse_fn_signature(decl).with_context(ctx)?,
     })
 }
 
@@ -232,20 +323,36 @@ enum DirectiveKind {
     DefinedIn,
     DefinedInStart,
     DefinedInEnd,
+    Include,
+    If,
+    Elif,
+    Else,
+    Endif,
 }
 
 type Trivia = Vec<String>;
 
 fn parse_directive_kind(token: &Token<'_>) -> Result<(DirectiveKind, Trivia)> {
     let ctx = parse_ctx!(token);
-    assert_eq!(token.get_kind(), TokenKind::Comment);
+    if token.get_kind() != TokenKind::Comment {
+        return Err(DirectiveError::UnexpectedTokenKind(
+            token_errloc(token),
+            token.get_kind(),
+        ))
+        .with_context(ctx);
+    }
     let spelling = token.get_spelling();
     let mut parts = spelling.split(':').map(|part| part.trim());
     let magic = parts
         .next()
         .ok_or(anyhow!("missing magic"))
         .with_context(ctx)?;
-    assert_eq!(magic, DIRECTIVE_MAGIC);
+    if magic != DIRECTIVE_MAGIC {
+        return Err(anyhow!(
+            "expected magic \"{DIRECTIVE_MAGIC}\", found \"{magic}\""
+        ))
+        .with_context(ctx);
+    }
     let directive = parts
         .next()
         .ok_or(anyhow!("missing directive"))
@@ -257,6 +364,11 @@ fn parse_directive_kind(token: &Token<'_>) -> Result<(DirectiveKind, Trivia)> {
         "Defined-in" => Ok((DirectiveKind::DefinedIn, trivia)),
         "Defined-in-start" => Ok((DirectiveKind::DefinedInStart, trivia)),
         "Defined-in-end" => Ok((DirectiveKind::DefinedInEnd, trivia)),
+        "Include" => Ok((DirectiveKind::Include, trivia)),
+        "If" => Ok((DirectiveKind::If, trivia)),
+        "Elif" => Ok((DirectiveKind::Elif, trivia)),
+        "Else" => Ok((DirectiveKind::Else, trivia)),
+        "Endif" => Ok((DirectiveKind::Endif, trivia)),
         _ => Err(anyhow!("unknown directive \"{}\"", directive)).with_context(ctx),
     }
 }
@@ -295,7 +407,8 @@ fn parse_defined_in_list(
         .collect();
     let signatures = cl::sonar::find_functions(nodes)
         .map(parse_fn_signature)
-        .collect();
+        .collect::<Result<Vec<_>, DirectiveError>>()
+        .with_context(ctx)?;
     Ok(Directive::DefinedInList {
         filename,
         signatures,
@@ -341,44 +454,578 @@ fn parse_directive(root: &Entity<'_>, token: &Token<'_>) -> Result<Directive> {
             parse_defined_in_list(root, token, filename.clone())
         }
         DirectiveKind::DefinedInEnd => Ok(Directive::DefinedInEnd),
+        DirectiveKind::Include => {
+            let path = trivia
+                .first()
+                .ok_or(anyhow!("missing include path"))
+                .with_context(ctx)?;
+            Ok(Directive::Include {
+                path: PathBuf::from(path),
+            })
+        }
+        DirectiveKind::If => {
+            let expr = trivia.join(":");
+            if expr.trim().is_empty() {
+                return Err(anyhow!("missing If expression")).with_context(ctx);
+            }
+            Ok(Directive::If { expr })
+        }
+        DirectiveKind::Elif => {
+            let expr = trivia.join(":");
+            if expr.trim().is_empty() {
+                return Err(anyhow!("missing Elif expression")).with_context(ctx);
+            }
+            Ok(Directive::Elif { expr })
+        }
+        DirectiveKind::Else => Ok(Directive::Else),
+        DirectiveKind::Endif => Ok(Directive::Endif),
+    }
+}
+
+type Symbols = FnvHashMap<String, i64>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ExprToken<'a> {
+    Int(i64),
+    Ident(&'a str),
+    LParen,
+    RParen,
+    Not,
+    Star,
+    Slash,
+    Plus,
+    Minus,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    EqEq,
+    Ne,
+    AndAnd,
+    OrOr,
+}
+
+fn tokenize_expr(expr: &str) -> Result<Vec<ExprToken<'_>>> {
+    let bytes = expr.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i] as char;
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '0'..='9' => {
+                let start = i;
+                while i < bytes.len() && (bytes[i] as char).is_ascii_digit() {
+                    i += 1;
+                }
+                let literal = &expr[start..i];
+                let value = literal
+                    .parse()
+                    .with_context(|| format!("invalid integer literal \"{literal}\""))?;
+                tokens.push(ExprToken::Int(value));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let start = i;
+                while i < bytes.len()
+                    && ((bytes[i] as char).is_alphanumeric() || bytes[i] as char == '_')
+                {
+                    i += 1;
+                }
+                tokens.push(ExprToken::Ident(&expr[start..i]));
+            }
+            '(' => {
+                tokens.push(ExprToken::LParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(ExprToken::RParen);
+                i += 1;
+            }
+            '*' => {
+                tokens.push(ExprToken::Star);
+                i += 1;
+            }
+            '/' => {
+                tokens.push(ExprToken::Slash);
+                i += 1;
+            }
+            '+' => {
+                tokens.push(ExprToken::Plus);
+                i += 1;
+            }
+            '-' => {
+                tokens.push(ExprToken::Minus);
+                i += 1;
+            }
+            '!' if bytes.get(i + 1) == Some(&b'=') => {
+                tokens.push(ExprToken::Ne);
+                i += 2;
+            }
+            '!' => {
+                tokens.push(ExprToken::Not);
+                i += 1;
+            }
+            '<' if bytes.get(i + 1) == Some(&b'=') => {
+                tokens.push(ExprToken::Le);
+                i += 2;
+            }
+            '<' => {
+                tokens.push(ExprToken::Lt);
+                i += 1;
+            }
+            '>' if bytes.get(i + 1) == Some(&b'=') => {
+                tokens.push(ExprToken::Ge);
+                i += 2;
+            }
+            '>' => {
+                tokens.push(ExprToken::Gt);
+                i += 1;
+            }
+            '=' if bytes.get(i + 1) == Some(&b'=') => {
+                tokens.push(ExprToken::EqEq);
+                i += 2;
+            }
+            '&' if bytes.get(i + 1) == Some(&b'&') => {
+                tokens.push(ExprToken::AndAnd);
+                i += 2;
+            }
+            '|' if bytes.get(i + 1) == Some(&b'|') => {
+                tokens.push(ExprToken::OrOr);
+                i += 2;
+            }
+            _ => bail!("unexpected character '{c}' in expression \"{expr}\""),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Precedence-climbing parser over the tokens of an `If`/`Elif` expression.
+/// Binding powers are spaced two apart so each precedence level gets a
+/// distinct (left, right) pair; `&&`/`||` short-circuit properly, skipping the
+/// right-hand side entirely (via `skip_expr`) rather than evaluating and
+/// discarding it.
+struct ExprParser<'a, 't> {
+    tokens: &'t [ExprToken<'a>],
+    pos: usize,
+    symbols: &'t Symbols,
+}
+
+impl<'a> ExprParser<'a, '_> {
+    fn peek(&self) -> Option<ExprToken<'a>> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<ExprToken<'a>> {
+        let token = self.peek();
+        if token.is_some() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn expect(&mut self, expected: ExprToken<'_>) -> Result<()> {
+        match self.bump() {
+            Some(token) if token == expected => Ok(()),
+            other => bail!("expected {expected:?}, found {other:?}"),
+        }
+    }
+
+    fn binding_power(op: ExprToken) -> Option<(u8, u8)> {
+        match op {
+            ExprToken::OrOr => Some((1, 2)),
+            ExprToken::AndAnd => Some((3, 4)),
+            ExprToken::EqEq | ExprToken::Ne => Some((5, 6)),
+            ExprToken::Lt | ExprToken::Le | ExprToken::Gt | ExprToken::Ge => Some((7, 8)),
+            ExprToken::Plus | ExprToken::Minus => Some((9, 10)),
+            ExprToken::Star | ExprToken::Slash => Some((11, 12)),
+            _ => None,
+        }
+    }
+
+    fn parse(&mut self) -> Result<i64> {
+        let value = self.parse_expr(0)?;
+        if self.pos != self.tokens.len() {
+            bail!("unexpected trailing tokens in expression");
+        }
+        Ok(value)
+    }
+
+    fn parse_expr(&mut self, min_bp: u8) -> Result<i64> {
+        let mut lhs = self.parse_unary()?;
+        while let Some(op) = self.peek() {
+            let Some((l_bp, r_bp)) = Self::binding_power(op) else {
+                break;
+            };
+            if l_bp < min_bp {
+                break;
+            }
+            self.bump();
+            lhs = match op {
+                ExprToken::AndAnd if lhs == 0 => {
+                    self.skip_expr(r_bp)?;
+                    0
+                }
+                ExprToken::OrOr if lhs != 0 => {
+                    self.skip_expr(r_bp)?;
+                    1
+                }
+                ExprToken::AndAnd | ExprToken::OrOr => (self.parse_expr(r_bp)? != 0) as i64,
+                ExprToken::Plus => {
+                    let rhs = self.parse_expr(r_bp)?;
+                    lhs.checked_add(rhs)
+                        .ok_or_else(|| anyhow!("overflow evaluating \"{lhs} + {rhs}\""))?
+                }
+                ExprToken::Minus => {
+                    let rhs = self.parse_expr(r_bp)?;
+                    lhs.checked_sub(rhs)
+                        .ok_or_else(|| anyhow!("overflow evaluating \"{lhs} - {rhs}\""))?
+                }
+                ExprToken::Star => {
+                    let rhs = self.parse_expr(r_bp)?;
+                    lhs.checked_mul(rhs)
+                        .ok_or_else(|| anyhow!("overflow evaluating \"{lhs} * {rhs}\""))?
+                }
+                ExprToken::Slash => {
+                    let rhs = self.parse_expr(r_bp)?;
+                    if rhs == 0 {
+                        bail!("division by zero in expression");
+                    }
+                    lhs.checked_div(rhs)
+                        .ok_or_else(|| anyhow!("overflow evaluating \"{lhs} / {rhs}\""))?
+                }
+                ExprToken::Lt => (lhs < self.parse_expr(r_bp)?) as i64,
+                ExprToken::Le => (lhs <= self.parse_expr(r_bp)?) as i64,
+                ExprToken::Gt => (lhs > self.parse_expr(r_bp)?) as i64,
+                ExprToken::Ge => (lhs >= self.parse_expr(r_bp)?) as i64,
+                ExprToken::EqEq => (lhs == self.parse_expr(r_bp)?) as i64,
+                ExprToken::Ne => (lhs != self.parse_expr(r_bp)?) as i64,
+                _ => unreachable!(),
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<i64> {
+        match self.peek() {
+            Some(ExprToken::Not) => {
+                self.bump();
+                Ok((self.parse_unary()? == 0) as i64)
+            }
+            Some(ExprToken::Minus) => {
+                self.bump();
+                self.parse_unary()?
+                    .checked_neg()
+                    .ok_or_else(|| anyhow!("overflow negating expression"))
+            }
+            _ => self.parse_primary(),
+        }
+    }
+
+    /// Advances past a subexpression without evaluating it, so a
+    /// short-circuited `&&`/`||` branch can't fail on the arm that was
+    /// never meant to run (e.g. `defined(X) && 100 / X`).
+    fn skip_unary(&mut self) -> Result<()> {
+        match self.peek() {
+            Some(ExprToken::Not | ExprToken::Minus) => {
+                self.bump();
+                self.skip_unary()
+            }
+            _ => self.skip_primary(),
+        }
+    }
+
+    fn skip_primary(&mut self) -> Result<()> {
+        match self.bump() {
+            Some(ExprToken::Int(_)) => Ok(()),
+            Some(ExprToken::Ident("defined")) => {
+                self.expect(ExprToken::LParen)?;
+                match self.bump() {
+                    Some(ExprToken::Ident(_)) => {}
+                    other => bail!("expected identifier in defined(), found {other:?}"),
+                }
+                self.expect(ExprToken::RParen)
+            }
+            Some(ExprToken::Ident(_)) => Ok(()),
+            Some(ExprToken::LParen) => {
+                self.skip_expr(0)?;
+                self.expect(ExprToken::RParen)
+            }
+            other => bail!("unexpected token in expression: {other:?}"),
+        }
+    }
+
+    fn skip_expr(&mut self, min_bp: u8) -> Result<()> {
+        self.skip_unary()?;
+        while let Some(op) = self.peek() {
+            let Some((l_bp, r_bp)) = Self::binding_power(op) else {
+                break;
+            };
+            if l_bp < min_bp {
+                break;
+            }
+            self.bump();
+            self.skip_expr(r_bp)?;
+        }
+        Ok(())
+    }
+
+    fn parse_primary(&mut self) -> Result<i64> {
+        match self.bump() {
+            Some(ExprToken::Int(value)) => Ok(value),
+            Some(ExprToken::Ident("defined")) => {
+                self.expect(ExprToken::LParen)?;
+                let name = match self.bump() {
+                    Some(ExprToken::Ident(name)) => name,
+                    other => bail!("expected identifier in defined(), found {other:?}"),
+                };
+                self.expect(ExprToken::RParen)?;
+                Ok(self.symbols.contains_key(name) as i64)
+            }
+            Some(ExprToken::Ident(name)) => Ok(*self.symbols.get(name).unwrap_or(&0)),
+            Some(ExprToken::LParen) => {
+                let value = self.parse_expr(0)?;
+                self.expect(ExprToken::RParen)?;
+                Ok(value)
+            }
+            other => bail!("unexpected token in expression: {other:?}"),
+        }
     }
 }
 
+fn eval_condition(expr: &str, symbols: &Symbols) -> Result<bool> {
+    let tokens = tokenize_expr(expr)?;
+    let mut parser = ExprParser {
+        tokens: &tokens,
+        pos: 0,
+        symbols,
+    };
+    Ok(parser.parse()? != 0)
+}
+
 #[derive(Debug, PartialEq)]
-enum ParseState {
+enum DefinedInState {
     Start,
     DefinedInBegin,
 }
 
-fn parse_directives<'tu, Ts>(root: &Entity<'tu>, raw_directives: Ts) -> Result<Vec<Directive>>
+struct ParseState {
+    defined_in: DefinedInState,
+    conditions: Vec<bool>,
+}
+
+impl ParseState {
+    fn new() -> Self {
+        Self {
+            defined_in: DefinedInState::Start,
+            conditions: Vec::new(),
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.conditions.last().copied().unwrap_or(true)
+    }
+}
+
+fn type_references(ty: &str, typename: &str) -> bool {
+    ty.split_whitespace().any(|part| part == typename)
+}
+
+fn signature_references(signature: &FuncDecl, typename: &str) -> bool {
+    type_references(&signature.return_ty, typename)
+        || signature
+            .params
+            .iter()
+            .any(|param| type_references(&param.ty, typename))
+}
+
+/// The result of scanning a single translation unit for directives: the
+/// directives themselves, non-fatal warnings about directives that parsed
+/// but likely don't do what the author intended, and hard errors recovered
+/// from while continuing to scan the rest of the file.
+struct ParseOutcome {
+    directives: Vec<Directive>,
+    warnings: Vec<String>,
+    errors: Vec<Error>,
+}
+
+fn parse_directives<'tu, Ts>(root: &Entity<'tu>, raw_directives: Ts, symbols: &Symbols) -> ParseOutcome
 where
     Ts: Iterator<Item = Token<'tu>>,
 {
+    let sourcedir = root
+        .get_display_name()
+        .map(PathBuf::from)
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+        .unwrap_or_else(|| PathBuf::from("."));
+
     let mut ds = Vec::new();
-    let mut state = ParseState::Start;
+    let mut warnings = Vec::new();
+    let mut errors = Vec::new();
+    let mut state = ParseState::new();
+    // Parallel to `state.conditions`: whether any branch of each open `If`
+    // chain has been taken yet.
+    let mut taken: Vec<bool> = Vec::new();
     for token in raw_directives {
         let ctx = parse_ctx!(&token);
-        let directive = parse_directive(root, &token)?;
-        match (&state, &directive) {
-            (ParseState::Start, Directive::DefinedInList { .. }) => {
-                state = ParseState::DefinedInBegin;
+        let directive = match parse_directive(root, &token) {
+            Ok(directive) => directive,
+            Err(err) => {
+                errors.push(err);
+                continue;
             }
-            (ParseState::Start, Directive::DefinedInEnd) => {
-                return Err(anyhow!("unmatched Defined-in-end")).with_context(ctx);
+        };
+        match &directive {
+            Directive::If { expr } => {
+                let parent_active = state.active();
+                let active = match eval_condition(expr, symbols) {
+                    Ok(value) => parent_active && value,
+                    Err(err) => {
+                        errors.push(err.context(ctx()));
+                        parent_active
+                    }
+                };
+                state.conditions.push(active);
+                taken.push(active);
+                continue;
+            }
+            Directive::Elif { expr } => {
+                let Some(current) = state.conditions.pop() else {
+                    errors.push(anyhow!("unmatched Elif").context(ctx()));
+                    continue;
+                };
+                let already_taken = taken.pop().unwrap_or(current);
+                let parent_active = state.active();
+                let active = if already_taken {
+                    Ok(false)
+                } else {
+                    eval_condition(expr, symbols).map(|value| parent_active && value)
+                };
+                match active {
+                    Ok(active) => {
+                        state.conditions.push(active);
+                        taken.push(already_taken || active);
+                    }
+                    Err(err) => {
+                        state.conditions.push(current);
+                        taken.push(already_taken);
+                        errors.push(err.context(ctx()));
+                    }
+                }
+                continue;
+            }
+            Directive::Else => {
+                let Some(current) = state.conditions.pop() else {
+                    errors.push(anyhow!("unmatched Else").context(ctx()));
+                    continue;
+                };
+                let already_taken = taken.pop().unwrap_or(current);
+                let parent_active = state.active();
+                let active = if already_taken { false } else { parent_active };
+                state.conditions.push(active);
+                taken.push(true);
+                continue;
+            }
+            Directive::Endif => {
+                if state.conditions.pop().is_none() {
+                    errors.push(anyhow!("unmatched Endif").context(ctx()));
+                }
+                taken.pop();
+                continue;
+            }
+            _ => {}
+        }
+
+        if !state.active() {
+            continue;
+        }
+
+        match (&state.defined_in, &directive) {
+            (DefinedInState::Start, Directive::DefinedInList { .. }) => {
+                state.defined_in = DefinedInState::DefinedInBegin;
             }
-            (ParseState::DefinedInBegin, Directive::DefinedInList { .. }) => {
-                return Err(anyhow!("nesting Defined-in-start is not allowed")).with_context(ctx);
+            (DefinedInState::Start, Directive::DefinedInEnd) => {
+                errors.push(anyhow!("unmatched Defined-in-end").context(ctx()));
+                continue;
+            }
+            (DefinedInState::DefinedInBegin, Directive::DefinedInList { .. }) => {
+                errors.push(anyhow!("nesting Defined-in-start is not allowed").context(ctx()));
+                continue;
             }
-            (ParseState::DefinedInBegin, Directive::DefinedInEnd) => {
-                state = ParseState::Start;
+            (DefinedInState::DefinedInBegin, Directive::DefinedInEnd) => {
+                state.defined_in = DefinedInState::Start;
                 continue;
             }
             _ => {}
         }
+
+        match &directive {
+            Directive::DefinedIn { signature, .. } if !signature.name.starts_with(HOOK_MAGIC) => {
+                warnings.push(format!(
+                    "{}: \"{}\" does not start with \"{HOOK_MAGIC}\"; no hook will be generated",
+                    ctx(),
+                    signature.name
+                ));
+            }
+            Directive::DefinedInList { signatures, .. } => {
+                for signature in signatures {
+                    if !signature.name.starts_with(HOOK_MAGIC) {
+                        warnings.push(format!(
+                            "{}: \"{}\" does not start with \"{HOOK_MAGIC}\"; no hook will be generated",
+                            ctx(),
+                            signature.name
+                        ));
+                    }
+                }
+            }
+            Directive::Include { path } => {
+                let resolved = sourcedir.join(path);
+                if !resolved.exists() {
+                    warnings.push(format!(
+                        "{}: include \"{}\" does not exist",
+                        ctx(),
+                        resolved.display()
+                    ));
+                }
+            }
+            Directive::BasedOn { filename, .. } => {
+                let resolved = sourcedir.join(filename);
+                if !resolved.exists() {
+                    warnings.push(format!(
+                        "{}: Based-on file \"{}\" does not exist",
+                        ctx(),
+                        resolved.display()
+                    ));
+                }
+            }
+            _ => {}
+        }
+
         ds.push(directive);
     }
-    assert_eq!(state, ParseState::Start);
-    Ok(ds)
+    if !state.conditions.is_empty() {
+        errors.push(anyhow!("unmatched If"));
+    }
+    if state.defined_in != DefinedInState::Start {
+        errors.push(anyhow!("unmatched Defined-in-start"));
+    }
+
+    ParseOutcome {
+        directives: ds,
+        warnings,
+        errors,
+    }
+}
+
+fn parse_define(raw: &str) -> Result<(String, i64), String> {
+    match raw.split_once('=') {
+        Some((name, value)) => {
+            let value = value
+                .parse()
+                .map_err(|_| format!("invalid value for -D{name}: expected an integer"))?;
+            Ok((name.to_string(), value))
+        }
+        None => Ok((raw.to_string(), 1)),
+    }
 }
 
 #[derive(Args)]
@@ -387,15 +1034,25 @@ struct ClangArgs {
     source_files: Vec<PathBuf>,
     #[arg(short = 'I')]
     include_dirs: Vec<PathBuf>,
+    #[arg(short = 'D', value_parser = parse_define)]
+    defines: Vec<(String, i64)>,
     #[arg(name = "CLANG_ARGS", last = true)]
     extra: Vec<String>,
 }
 
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum ScanFormat {
+    Debug,
+    Json,
+}
+
 #[derive(Subcommand)]
 enum CliCmd {
     ScanDump {
         #[command(flatten)]
         clang_args: ClangArgs,
+        #[arg(long, value_enum, default_value = "debug")]
+        format: ScanFormat,
     },
     WriteHooks {
         #[command(flatten)]
@@ -408,18 +1065,138 @@ enum CliCmd {
 impl CliCmd {
     fn clang_args(&self) -> &ClangArgs {
         match self {
-            Self::ScanDump { clang_args } => clang_args,
+            Self::ScanDump { clang_args, .. } => clang_args,
             Self::WriteHooks { clang_args, .. } => clang_args,
         }
     }
 }
 
-fn scan_directives(tu: &cl::TranslationUnit) -> Result<Vec<Directive>> {
+fn scan_directives(tu: &cl::TranslationUnit, symbols: &Symbols) -> Result<ParseOutcome> {
     let root = tu.get_entity();
     let range = root
         .get_range()
         .ok_or(anyhow!("root entity should not be empty"))?;
-    parse_directives(&root, iter_raw_directives(range))
+    Ok(parse_directives(&root, iter_raw_directives(range), symbols))
+}
+
+fn resolve_include(from: &Path, include: &Path) -> Result<PathBuf> {
+    let candidate = from.parent().unwrap_or_else(|| Path::new(".")).join(include);
+    candidate
+        .canonicalize()
+        .with_context(|| format!("{} does not exist", candidate.display()))
+}
+
+/// Resolves `// ABC_MINI: Include:` directives transitively, so that directives
+/// declared anywhere in the include graph are visible when generating hooks.
+struct Loader {
+    directives: FnvHashMap<PathBuf, Vec<Directive>>,
+    visited: HashSet<PathBuf>,
+    warnings: Vec<String>,
+    errors: Vec<Error>,
+}
+
+impl Loader {
+    fn new() -> Self {
+        Self {
+            directives: FnvHashMap::default(),
+            visited: HashSet::default(),
+            warnings: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    fn load<Rs>(
+        &mut self,
+        index: &cl::Index,
+        clang_args: &[String],
+        symbols: &Symbols,
+        roots: Rs,
+    ) -> Result<()>
+    where
+        Rs: IntoIterator<Item = PathBuf>,
+    {
+        let mut stack = roots
+            .into_iter()
+            .map(|root| -> Result<(PathBuf, Vec<PathBuf>)> {
+                if !root.exists() {
+                    bail!("{} does not exist", root.display());
+                }
+                Ok((root.canonicalize()?, Vec::new()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        while let Some((source, path)) = stack.pop() {
+            if self.visited.contains(&source) {
+                continue;
+            }
+            self.visited.insert(source.clone());
+
+            let tu = index
+                .parser(source.clone())
+                .arguments(clang_args)
+                .parse()
+                .with_context(|| format!("Failed to parse {}", source.display()))?;
+            let outcome = scan_directives(&tu, symbols)?;
+            self.warnings.extend(outcome.warnings);
+            self.errors.extend(outcome.errors);
+
+            let mut resolution_path = path;
+            resolution_path.push(source.clone());
+
+            for directive in &outcome.directives {
+                if let Directive::Include { path: include } = directive {
+                    // A missing include is already reported as a warning by
+                    // `parse_directives`; there is simply nothing further to follow.
+                    if let Ok(resolved) = resolve_include(&source, include) {
+                        if resolution_path.contains(&resolved) {
+                            bail!(
+                                "cyclic include: {} includes {} which is already on the resolution path",
+                                source.display(),
+                                resolved.display()
+                            );
+                        }
+                        if !self.visited.contains(&resolved) {
+                            stack.push((resolved, resolution_path.clone()));
+                        }
+                    }
+                }
+            }
+
+            self.directives.insert(source, outcome.directives);
+        }
+
+        let unreferenced_aliases: Vec<String> = self
+            .directives()
+            .filter_map(|directive| match directive {
+                Directive::AliasOf { typename, .. } => Some(typename.as_str()),
+                _ => None,
+            })
+            .filter(|typename| {
+                !self.directives().any(|other| match other {
+                    Directive::DefinedIn { signature, .. } => {
+                        signature_references(signature, typename)
+                    }
+                    Directive::DefinedInList { signatures, .. } => signatures
+                        .iter()
+                        .any(|signature| signature_references(signature, typename)),
+                    _ => false,
+                })
+            })
+            .map(str::to_string)
+            .collect();
+        self.warnings
+            .extend(unreferenced_aliases.into_iter().map(|typename| {
+                format!(
+                    "\"Alias-of: {typename}\" target type is never referenced by a Defined-in signature"
+                )
+            }));
+
+        Ok(())
+    }
+
+    fn directives(&self) -> impl Iterator<Item = &Directive> {
+        self.directives.values().flatten()
+    }
 }
 
 struct Rewrites<'a>(FnvHashMap<&'a str, &'a str>);
@@ -442,9 +1219,12 @@ impl Rewrites<'_> {
     }
 }
 
-impl<'a> From<&'a Vec<Directive>> for Rewrites<'a> {
-    fn from(directives: &'a Vec<Directive>) -> Self {
-        Self(FnvHashMap::from_iter(directives.iter().filter_map(
+impl<'a> FromIterator<&'a Directive> for Rewrites<'a> {
+    fn from_iter<Ds>(directives: Ds) -> Self
+    where
+        Ds: IntoIterator<Item = &'a Directive>,
+    {
+        Self(FnvHashMap::from_iter(directives.into_iter().filter_map(
             |directive| match directive {
                 Directive::AliasOf { typename, alias } => {
                     Some((alias.name.as_str(), typename.as_str()))
@@ -545,7 +1325,10 @@ fn render_function_hook(function: &FuncDecl, rewrites: &Rewrites) -> Vec<String>
 
 const HOOK_MAGIC: &str = "AbcMini__";
 
-fn generate_hooks(directives: &Vec<Directive>) -> Vec<FuncHooks> {
+fn generate_hooks<'a, Ds>(directives: Ds) -> Vec<FuncHooks>
+where
+    Ds: IntoIterator<Item = &'a Directive>,
+{
     let mut hooks = FnvHashMap::<&str, Vec<FuncDecl>>::default();
     for directive in directives {
         match directive {
@@ -593,8 +1376,21 @@ fn generate_hooks(directives: &Vec<Directive>) -> Vec<FuncHooks> {
 const HOOK_HEADER: &str = "// AUTO-GENERATED BY ABC-MINI DEPTOOL -- DO NOT MODIFY";
 const HOOK_FOOTER: &str = "// END AUTO-GENERATED BY ABC-MINI DEPTOOL";
 
+fn render_forward_declaration(function: &FuncDecl, rewrites: &Rewrites) -> String {
+    format!("extern {};", render_function_declaration(function, rewrites))
+}
+
 fn render_payload(hooks: &FuncHooks, rewrites: &Rewrites) -> String {
     let mut payload = format!("{HOOK_HEADER}\n");
+    payload.push_str("#pragma once\n\n");
+    payload.push_str("#ifdef __cplusplus\nextern \"C\" {\n#endif\n\n");
+    for function in &hooks.functions {
+        payload.push_str(&format!(
+            "{}\n",
+            render_forward_declaration(function, rewrites)
+        ));
+    }
+    payload.push('\n');
     for line in hooks
         .functions
         .iter()
@@ -602,6 +1398,7 @@ fn render_payload(hooks: &FuncHooks, rewrites: &Rewrites) -> String {
     {
         payload.push_str(&format!("{line}\n"));
     }
+    payload.push_str("\n#ifdef __cplusplus\n}\n#endif\n");
     payload.push_str(&format!("{HOOK_FOOTER}\n"));
     payload
 }
@@ -685,8 +1482,10 @@ fn deliver_hooks(hooks: &FuncHooks, rewrites: &Rewrites, payload_dir: &Path) ->
     match (hooks_start, hooks_end, include_found) {
         (Some(_), Some(_), IncludeFound::Yes) => {}
         (Some(_), Some(hooks_end), IncludeFound::No) => {
-            //
-            todo!()
+            let mut lines: Vec<&str> = target_content.split('\n').collect();
+            let include_line = format!("#include \"{hookfile_include}\"");
+            lines.insert(hooks_end, &include_line);
+            fs::write(target, lines.join("\n"))?;
         }
         (None, None, IncludeFound::No) => {
             let sep = if target_content.ends_with('\n') {
@@ -723,30 +1522,55 @@ fn handle_command(cmd: CliCmd) -> Result<()> {
         .collect::<Vec<_>>();
     let clang = Clang::new().map_err(Error::msg)?;
     let index = cl::Index::new(&clang, false, false);
-    for source in cmd.clang_args().source_files.iter() {
-        if !source.exists() {
-            bail!("{} does not exist", source.display());
-        }
-        let tu = index
-            .parser(source.clone())
-            .arguments(&clang_args)
-            .parse()
-            .with_context(|| format!("Failed to parse {}", source.display()))?;
-        match cmd {
-            CliCmd::ScanDump { .. } => {
-                let directives = scan_directives(&tu)?;
-                println!("{}: {:#?}", source.display(), directives);
-            }
-            CliCmd::WriteHooks {
-                ref payload_srcdir, ..
-            } => {
-                let payload_srcdir = payload_srcdir.as_ref().unwrap_or(&working_dir);
-                let directives = scan_directives(&tu)?;
-                let rewrites = Rewrites::from(&directives);
-                for hooks in generate_hooks(&directives) {
-                    deliver_hooks(&hooks, &rewrites, payload_srcdir)?;
+    let symbols: Symbols = cmd.clang_args().defines.iter().cloned().collect();
+
+    let mut loader = Loader::new();
+    loader.load(
+        &index,
+        &clang_args,
+        &symbols,
+        cmd.clang_args().source_files.iter().cloned(),
+    )?;
+
+    for warning in &loader.warnings {
+        eprintln!("warning: {warning}");
+    }
+    if !loader.errors.is_empty() {
+        for error in &loader.errors {
+            eprintln!("error: {error:?}");
+        }
+        bail!(
+            "{} directive error(s) encountered; see above",
+            loader.errors.len()
+        );
+    }
+
+    match cmd {
+        CliCmd::ScanDump { format, .. } => match format {
+            ScanFormat::Debug => {
+                let mut sources: Vec<_> = loader.directives.keys().collect();
+                sources.sort();
+                for source in sources {
+                    println!("{}: {:#?}", source.display(), loader.directives[source]);
                 }
             }
+            ScanFormat::Json => {
+                let dump: BTreeMap<String, &Vec<Directive>> = loader
+                    .directives
+                    .iter()
+                    .map(|(source, directives)| (source.display().to_string(), directives))
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&dump)?);
+            }
+        },
+        CliCmd::WriteHooks {
+            ref payload_srcdir, ..
+        } => {
+            let payload_srcdir = payload_srcdir.as_ref().unwrap_or(&working_dir);
+            let rewrites = Rewrites::from_iter(loader.directives());
+            for hooks in generate_hooks(loader.directives()) {
+                deliver_hooks(&hooks, &rewrites, payload_srcdir)?;
+            }
         }
     }
     Ok(())